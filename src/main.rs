@@ -1,25 +1,290 @@
 // SPDX-License-Identifier: MIT
 
 use futures::TryStreamExt;
-use nix::fcntl::{open, OFlag};
+use nix::errno::Errno;
+use nix::fcntl::{open, openat, OFlag};
 use nix::mount::{mount, MsFlags};
 use nix::sched::{CloneFlags, unshare, setns};
-use nix::unistd::{fork, ForkResult, Pid};
+use nix::unistd::{close, execvp, fork, read, write as nix_write, ForkResult, Gid, Pid, Uid};
+use nix::sys::prctl;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
 use nix::sys::wait::{waitpid, WaitStatus};
 use nix::sys::stat::Mode;
 use nix::sys::statvfs::{statvfs, FsFlags};
-use rtnetlink::{new_connection, Error, Handle, NetworkNamespace};
+use rtnetlink::{new_connection, Error, NetworkNamespace};
 
 use std::env;
-use std::fs::{File, OpenOptions};
+use std::ffi::CString;
+use std::fs::File;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::os::unix::io::RawFd;
-use std::os::fd::FromRawFd;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 
 static NETNS: &str = "/run/netns/";
 
+// How long to retry opening a freshly-created netns path before giving up --
+// NetworkNamespace::add() can return before the bind-mounted path is visible.
+static NS_WAIT_TIMEOUT: Duration = Duration::from_secs(2);
+static NS_WAIT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// How often the optional watchdog re-checks that the target netns path
+// still exists while the exec'd command is running.
+static WATCHDOG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Table of namespace kinds this tool knows how to join, mapping the CLI
+// spelling to its clone(2) flag and its /proc/<pid>/ns/<name> suffix.
+struct NsKind {
+    name: &'static str,
+    flag: CloneFlags,
+    proc_name: &'static str,
+}
+
+static NS_KINDS: &[NsKind] = &[
+    NsKind { name: "cgroup", flag: CloneFlags::CLONE_NEWCGROUP, proc_name: "cgroup" },
+    NsKind { name: "ipc", flag: CloneFlags::CLONE_NEWIPC, proc_name: "ipc" },
+    NsKind { name: "net", flag: CloneFlags::CLONE_NEWNET, proc_name: "net" },
+    NsKind { name: "mnt", flag: CloneFlags::CLONE_NEWNS, proc_name: "mnt" },
+    NsKind { name: "pid", flag: CloneFlags::CLONE_NEWPID, proc_name: "pid" },
+    NsKind { name: "user", flag: CloneFlags::CLONE_NEWUSER, proc_name: "user" },
+    NsKind { name: "uts", flag: CloneFlags::CLONE_NEWUTS, proc_name: "uts" },
+];
+
+fn lookup_ns_kind(name: &str) -> Option<&'static NsKind> {
+    NS_KINDS.iter().find(|k| k.name == name)
+}
+
+// A user-supplied `type:path` namespace specifier, e.g. `uts:/proc/1234/ns/uts`.
+struct NsSpec {
+    kind: &'static NsKind,
+    path: PathBuf,
+}
+
+impl NsSpec {
+    // Accepts either `type:path` (join the namespace found at that path) or
+    // `type:pid` (join the namespace PID <pid> is currently in, shorthand
+    // for `type:/proc/<pid>/ns/<proc_name>`).
+    fn parse(raw: &str) -> Result<Self, String> {
+        let (kind_name, rest) = raw.split_once(':')
+            .ok_or_else(|| format!("invalid namespace specifier '{}', expected type:path", raw))?;
+        let kind = lookup_ns_kind(kind_name)
+            .ok_or_else(|| format!("unknown namespace type '{}'", kind_name))?;
+        let path = match rest.parse::<u32>() {
+            Ok(pid) => PathBuf::from(format!("/proc/{}/ns/{}", pid, kind.proc_name)),
+            Err(_) => PathBuf::from(rest),
+        };
+        Ok(NsSpec { kind, path })
+    }
+}
+
+// Configuration for the veth pair connecting the target net namespace back
+// to the caller's: `veth_name`/`veth_addr` stay on our side, `peer_name`/
+// `peer_addr` are moved into the namespace, `gateway` becomes its default route.
+struct VethConfig {
+    veth_name: String,
+    veth_addr: (IpAddr, u8),
+    peer_name: String,
+    peer_addr: (IpAddr, u8),
+    gateway: Option<IpAddr>,
+}
+
+fn parse_cidr(raw: &str) -> Result<(IpAddr, u8), String> {
+    let (addr, prefix) = raw.split_once('/')
+        .ok_or_else(|| format!("invalid address '{}', expected addr/prefix", raw))?;
+    let addr: IpAddr = addr.parse().map_err(|e| format!("invalid address '{}': {}", addr, e))?;
+    let prefix: u8 = prefix.parse().map_err(|e| format!("invalid prefix '{}': {}", prefix, e))?;
+    Ok((addr, prefix))
+}
+
+impl VethConfig {
+    fn parse(veth_name: &str, veth_addr: &str, peer_name: &str, peer_addr: &str, gateway: Option<&str>) -> Result<Self, String> {
+        let gateway = gateway.map(|g| g.parse::<IpAddr>().map_err(|e| format!("invalid gateway '{}': {}", g, e))).transpose()?;
+        Ok(VethConfig {
+            veth_name: veth_name.to_string(),
+            veth_addr: parse_cidr(veth_addr)?,
+            peer_name: peer_name.to_string(),
+            peer_addr: parse_cidr(peer_addr)?,
+            gateway,
+        })
+    }
+}
+
+// A single `/proc/<pid>/{uid,gid}_map` line: map `length` ids starting at
+// `outside` (our namespace) onto ids starting at `inside` (the new one).
+struct UidGidMap {
+    inside: u32,
+    outside: u32,
+    length: u32,
+}
+
+fn parse_id_map(raw: &str) -> Result<UidGidMap, String> {
+    let parts: Vec<&str> = raw.split(':').collect();
+    if parts.len() != 3 {
+        return Err(format!("invalid id map '{}', expected inside:outside:length", raw));
+    }
+    let inside = parts[0].parse().map_err(|e| format!("invalid inside id '{}': {}", parts[0], e))?;
+    let outside = parts[1].parse().map_err(|e| format!("invalid outside id '{}': {}", parts[1], e))?;
+    let length = parts[2].parse().map_err(|e| format!("invalid length '{}': {}", parts[2], e))?;
+    Ok(UidGidMap { inside, outside, length })
+}
+
+// Requested uid/gid mappings for a rootless (CLONE_NEWUSER) run. Defaults to
+// mapping the caller's euid/egid to root-in-namespace, as `unshare -r` does.
+struct UserNsConfig {
+    uid_maps: Vec<UidGidMap>,
+    gid_maps: Vec<UidGidMap>,
+}
+
+impl UserNsConfig {
+    fn default_uid_map() -> UidGidMap {
+        UidGidMap { inside: 0, outside: Uid::effective().as_raw(), length: 1 }
+    }
+
+    fn default_gid_map() -> UidGidMap {
+        UidGidMap { inside: 0, outside: Gid::effective().as_raw(), length: 1 }
+    }
+}
+
+// Milestones the init (forked child) process reports as it sets up the
+// namespaces, plus the replies the main (parent) process sends back once
+// it has completed the matching piece of work on its own side. Lets the
+// two processes interleave steps that must happen in a strict order
+// (writing uid/gid maps, confirming the netns path exists) instead of the
+// parent only ever doing a final `waitpid`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Checkpoint {
+    EnteredUserNs,
+    MapsWritten,
+    CreatedNetns,
+    ReadyForConfig,
+    ConfigDone,
+    CreatedPidNs,
+}
+
+impl Checkpoint {
+    fn to_byte(self) -> u8 {
+        match self {
+            Checkpoint::EnteredUserNs => 1,
+            Checkpoint::MapsWritten => 2,
+            Checkpoint::CreatedNetns => 3,
+            Checkpoint::ReadyForConfig => 4,
+            Checkpoint::ConfigDone => 5,
+            Checkpoint::CreatedPidNs => 6,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, ()> {
+        match byte {
+            1 => Ok(Checkpoint::EnteredUserNs),
+            2 => Ok(Checkpoint::MapsWritten),
+            3 => Ok(Checkpoint::CreatedNetns),
+            4 => Ok(Checkpoint::ReadyForConfig),
+            5 => Ok(Checkpoint::ConfigDone),
+            6 => Ok(Checkpoint::CreatedPidNs),
+            _ => {
+                log::error!("Unknown checkpoint byte: {}", byte);
+                Err(())
+            }
+        }
+    }
+}
+
+fn send_checkpoint(fd: RawFd, cp: Checkpoint) -> Result<(), ()> {
+    nix_write(fd, &[cp.to_byte()]).map(|_| ()).map_err(|e| log::error!("Can not send checkpoint {:?}: {}", cp, e))
+}
+
+fn recv_checkpoint(fd: RawFd) -> Result<Checkpoint, ()> {
+    let mut buf = [0u8; 1];
+    let n = read(fd, &mut buf).map_err(|e| log::error!("Can not receive checkpoint: {}", e))?;
+    if n == 0 {
+        log::error!("Sync channel closed before sending a checkpoint");
+        return Err(());
+    }
+    Checkpoint::from_byte(buf[0])
+}
+
+fn expect_checkpoint(fd: RawFd, expected: Checkpoint) -> Result<(), ()> {
+    let got = recv_checkpoint(fd)?;
+    if got != expected {
+        log::error!("Expected checkpoint {:?}, got {:?}", expected, got);
+        return Err(());
+    }
+    Ok(())
+}
+
+// A raw pid_t, sent immediately after a Checkpoint::CreatedPidNs signal --
+// used to report the grandchild PID of a `--newpid` double-fork back to
+// run_parent, since a plain Checkpoint byte can't carry a payload.
+fn send_pid(fd: RawFd, pid: Pid) -> Result<(), ()> {
+    nix_write(fd, &pid.as_raw().to_ne_bytes()).map(|_| ()).map_err(|e| log::error!("Can not send pid: {}", e))
+}
+
+fn recv_pid(fd: RawFd) -> Result<Pid, ()> {
+    let mut buf = [0u8; 4];
+    let n = read(fd, &mut buf).map_err(|e| log::error!("Can not receive pid: {}", e))?;
+    if n != buf.len() {
+        log::error!("Sync channel closed while receiving pid");
+        return Err(());
+    }
+    Ok(Pid::from_raw(i32::from_ne_bytes(buf)))
+}
+
+// The main process's half of the sync channel: waits for the init
+// process's checkpoints and acks the ones that need a reply.
+struct MainSender(RawFd);
+
+impl MainSender {
+    fn wait_for(&self, expected: Checkpoint) -> Result<(), ()> {
+        expect_checkpoint(self.0, expected)
+    }
+
+    fn ack(&self, cp: Checkpoint) -> Result<(), ()> {
+        send_checkpoint(self.0, cp)
+    }
+
+    // Receive the grandchild PID reported by InitReceiver::report_pid_ns().
+    fn recv_grandchild_pid(&self) -> Result<Pid, ()> {
+        expect_checkpoint(self.0, Checkpoint::CreatedPidNs)?;
+        recv_pid(self.0)
+    }
+}
+
+impl Drop for MainSender {
+    fn drop(&mut self) {
+        let _ = close(self.0);
+    }
+}
+
+// The init (forked child) process's half of the sync channel.
+struct InitReceiver(RawFd);
+
+impl InitReceiver {
+    fn signal(&self, cp: Checkpoint) -> Result<(), ()> {
+        send_checkpoint(self.0, cp)
+    }
+
+    fn wait_for(&self, expected: Checkpoint) -> Result<(), ()> {
+        expect_checkpoint(self.0, expected)
+    }
+
+    // Report the PID of the grandchild created by the --newpid double-fork.
+    fn report_pid_ns(&self, pid: Pid) -> Result<(), ()> {
+        self.signal(Checkpoint::CreatedPidNs)?;
+        send_pid(self.0, pid)
+    }
+}
+
+impl Drop for InitReceiver {
+    fn drop(&mut self) {
+        let _ = close(self.0);
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), String> {
 
@@ -29,18 +294,115 @@ async fn main() -> Result<(), String> {
         .init();
 
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
+    if args.len() < 2 {
         usage();
         return Ok(());
     }
     let ns_name = &args[1];
-    run_in_namespace(ns_name).await.unwrap();
+    let rest = &args[2..];
+    let sep = rest.iter().position(|a| a == "--");
+    let (spec_args, command): (&[String], &[String]) = match sep {
+        Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+        None => (&[], rest),
+    };
+    if command.is_empty() {
+        usage();
+        return Ok(());
+    }
+    let (specs, veth, userns, watchdog, want_newpid) = match parse_spec_args(spec_args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            log::error!("{}", e);
+            usage();
+            return Ok(());
+        }
+    };
+    let code = run_in_namespace(ns_name, &specs, veth.as_ref(), userns.as_ref(), watchdog, want_newpid, command).await.unwrap_or(1);
 
-    Ok(())
+    exit(code);
+}
+
+// Parse the arguments between <ns_name> and `--`: a mix of `type:path`
+// namespace specifiers, an optional `--veth <name> <addr/prefix> <peer_name>
+// <peer_addr/prefix> [gateway]` flag, optional `--userns`/`--uid-map`/
+// `--gid-map inside:outside:length` flags for a rootless run, an optional
+// `--watchdog` flag to supervise the target netns, and an optional
+// `--newpid` flag to run the command as PID 1 of a fresh pid namespace.
+// (namespace specs, veth config, userns config, watchdog, want_newpid)
+type SpecArgs = (Vec<NsSpec>, Option<VethConfig>, Option<UserNsConfig>, bool, bool);
+
+fn parse_spec_args(args: &[String]) -> Result<SpecArgs, String> {
+    let mut specs = Vec::new();
+    let mut veth = None;
+    let mut want_userns = false;
+    let mut watchdog = false;
+    let mut want_newpid = false;
+    let mut uid_maps = Vec::new();
+    let mut gid_maps = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--veth" => {
+                if i + 4 >= args.len() {
+                    return Err("--veth requires <name> <addr/prefix> <peer_name> <peer_addr/prefix> [gateway]".to_string());
+                }
+                // Only treat the 5th token as a gateway if it actually parses as one --
+                // otherwise it's the next `type:path` namespace spec (or flag) and must
+                // be left for the next iteration.
+                let gateway = args.get(i + 5).filter(|a| a.parse::<IpAddr>().is_ok());
+                veth = Some(VethConfig::parse(&args[i + 1], &args[i + 2], &args[i + 3], &args[i + 4], gateway.map(|s| s.as_str()))?);
+                i += if gateway.is_some() { 6 } else { 5 };
+            }
+            "--userns" => {
+                want_userns = true;
+                i += 1;
+            }
+            "--uid-map" => {
+                let raw = args.get(i + 1).ok_or_else(|| "--uid-map requires inside:outside:length".to_string())?;
+                uid_maps.push(parse_id_map(raw)?);
+                want_userns = true;
+                i += 2;
+            }
+            "--gid-map" => {
+                let raw = args.get(i + 1).ok_or_else(|| "--gid-map requires inside:outside:length".to_string())?;
+                gid_maps.push(parse_id_map(raw)?);
+                want_userns = true;
+                i += 2;
+            }
+            "--watchdog" => {
+                watchdog = true;
+                i += 1;
+            }
+            "--newpid" => {
+                want_newpid = true;
+                i += 1;
+            }
+            other => {
+                specs.push(NsSpec::parse(other)?);
+                i += 1;
+            }
+        }
+    }
+    let userns = want_userns.then(|| UserNsConfig {
+        uid_maps: if uid_maps.is_empty() { vec![UserNsConfig::default_uid_map()] } else { uid_maps },
+        gid_maps: if gid_maps.is_empty() { vec![UserNsConfig::default_gid_map()] } else { gid_maps },
+    });
+    Ok((specs, veth, userns, watchdog, want_newpid))
 }
 
-pub async fn run_in_namespace(ns_name: &String) -> Result<(), ()> {
-    prep_for_fork()?;
+async fn run_in_namespace(ns_name: &String, extra_ns: &[NsSpec], veth: Option<&VethConfig>, userns: Option<&UserNsConfig>, watchdog: bool, want_newpid: bool, command: &[String]) -> Result<i32, ()> {
+    // With --newpid the process that unshares CLONE_NEWPID forks again and
+    // exits immediately, orphaning the grandchild that actually execs the
+    // command. Mark ourselves a child subreaper so that grandchild reparents
+    // to us (instead of the host's PID 1) and we can still waitpid() it.
+    if want_newpid {
+        prctl::set_child_subreaper(true).map_err(|e| {
+            log::error!("Can not mark process as child subreaper: {}", e);
+        })?;
+    }
+
+    let (main_fd, init_fd) = prep_for_fork()?;
+
     // Configure networking in the child namespace:
     // Fork a process that is set to the newly created namespace
     // Here set the veth ip addr, routing tables etc.
@@ -50,53 +412,160 @@ pub async fn run_in_namespace(ns_name: &String) -> Result<(), ()> {
         Ok(ForkResult::Parent { child, .. }) => {
             // Parent process
             log::debug!("Net configuration PID: {}", child.as_raw());
-            run_parent(child)
+            close(init_fd).ok();
+            run_parent(child, userns, MainSender(main_fd), ns_name, watchdog, want_newpid).await
         }
         Ok(ForkResult::Child) => {
             // Child process
             // Move the child to the target namespace
-            run_child(ns_name).await
+            close(main_fd).ok();
+            run_child(ns_name, extra_ns, veth, userns.is_some(), want_newpid, InitReceiver(init_fd), command).await
         }
         Err(e) => {
             log::error!("Can not fork() for ns creation: {}", e);
-            return Err(());
+            close(main_fd).ok();
+            close(init_fd).ok();
+            Err(())
         }
     }
 
 }
 
-fn run_parent(child: Pid) -> Result<(), ()> {
+// Write /proc/<child>/{uid_map,gid_map}. setgroups must be denied before
+// gid_map can be written by an unprivileged process (see user_namespaces(7)).
+fn write_uid_gid_maps(child: Pid, cfg: &UserNsConfig) -> Result<(), ()> {
+    write_id_map(child, "uid_map", &cfg.uid_maps)?;
+    write_proc_file(child, "setgroups", "deny")?;
+    write_id_map(child, "gid_map", &cfg.gid_maps)?;
+    Ok(())
+}
+
+fn write_id_map(child: Pid, file: &str, maps: &[UidGidMap]) -> Result<(), ()> {
+    let contents: String = maps.iter()
+        .map(|m| format!("{} {} {}\n", m.inside, m.outside, m.length))
+        .collect();
+    write_proc_file(child, file, &contents)
+}
+
+fn write_proc_file(child: Pid, file: &str, contents: &str) -> Result<(), ()> {
+    let path = format!("/proc/{}/{}", child.as_raw(), file);
+    std::fs::write(&path, contents).map_err(|e| {
+        log::error!("Can not write {}: {}", path, e);
+    })
+}
+
+async fn run_parent(child: Pid, userns: Option<&UserNsConfig>, channel: MainSender, ns_name: &str, watchdog: bool, want_newpid: bool) -> Result<i32, ()> {
     log::trace!("[Parent] Child PID: {}", child);
-    match waitpid(child, None) {
+
+    if let Some(cfg) = userns {
+        channel.wait_for(Checkpoint::EnteredUserNs)?;
+        write_uid_gid_maps(child, cfg)?;
+        channel.ack(Checkpoint::MapsWritten)?;
+    }
+    channel.wait_for(Checkpoint::CreatedNetns)?;
+    channel.wait_for(Checkpoint::ReadyForConfig)?;
+    channel.wait_for(Checkpoint::ConfigDone)?;
+
+    // With --newpid, `child` unshared CLONE_NEWPID and forked again: it is
+    // not itself a member of the new namespace, so it reports the
+    // grandchild's PID over the channel and exits right away. Reap that
+    // intermediate here, then track the grandchild (which actually execs
+    // the command) for the rest of this function.
+    let exec_pid = if want_newpid {
+        let grandchild = channel.recv_grandchild_pid()?;
+        drop(channel);
+        match tokio::task::spawn_blocking(move || waitpid(child, None)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => log::error!("Can not reap pid-namespace intermediate: {}", e),
+            Err(e) => log::error!("wait task panicked: {}", e),
+        }
+        grandchild
+    } else {
+        drop(channel);
+        child
+    };
+
+    // Once the child has exec'd, optionally keep watching the target netns
+    // so we notice (and react to) it being torn out from under the command.
+    let watchdog_task = watchdog.then(|| tokio::spawn(watch_namespace(ns_name.to_owned(), exec_pid)));
+
+    let wait_status = tokio::task::spawn_blocking(move || waitpid(exec_pid, None))
+        .await
+        .map_err(|e| log::error!("wait task panicked: {}", e))?;
+
+    if let Some(task) = watchdog_task {
+        task.abort();
+    }
+
+    match wait_status {
         Ok(wait_status) => match wait_status {
             WaitStatus::Exited(_, res) => {
                 log::trace!("Child exited with: {}", res);
-                if res == 0 {
-                    return Ok(());
-                } else {
-                    log::error!("Child exited with status {}", res);
-                    return Err(());
-                }
+                Ok(res)
             }
             WaitStatus::Signaled(_, signal, coredump) => {
-                log::error!("Child process killed by signal");
-                return Err(());
+                log::error!("Child process killed by signal {:?} (core dumped: {})", signal, coredump);
+                Ok(128 + signal as i32)
             }
             _ => {
                 log::error!("Unknown child process status: {:?}", wait_status);
-                return Err(());
+                Err(())
             }
         }
         Err(e) => {
             log::error!("wait error : {}", e);
-            return Err(());
+            Err(())
         }
     }
 
 }
 
-async fn run_child(ns_name: &String) -> Result<(), ()> {
-    let res = split_namespace(ns_name).await;
+// Keep `/run/netns` open as a directory fd and periodically `openat`
+// the `<ns_name>` namespace file inside it: if that starts failing with
+// ENOENT, someone deleted the namespace out from under the exec'd command,
+// so terminate it rather than leaving it running headless. A directory fd
+// + poll avoids relying on inotify, whose watch limits can be exhausted on
+// busy hosts.
+async fn watch_namespace(ns_name: String, child: Pid) {
+    let mut dir_flags = OFlag::empty();
+    dir_flags.insert(OFlag::O_DIRECTORY);
+    dir_flags.insert(OFlag::O_RDONLY);
+    dir_flags.insert(OFlag::O_CLOEXEC);
+    let dir_fd = match open(Path::new(NETNS), dir_flags, Mode::empty()) {
+        Ok(fd) => fd,
+        Err(e) => {
+            log::error!("Watchdog can not open {}: {}", NETNS, e);
+            return;
+        }
+    };
+
+    let mut open_flags = OFlag::empty();
+    open_flags.insert(OFlag::O_RDONLY);
+    open_flags.insert(OFlag::O_CLOEXEC);
+
+    let mut ticker = tokio::time::interval(WATCHDOG_POLL_INTERVAL);
+    loop {
+        ticker.tick().await;
+        match openat(dir_fd, ns_name.as_str(), open_flags, Mode::empty()) {
+            Ok(fd) => {
+                close(fd).ok();
+            }
+            Err(Errno::ENOENT) => {
+                log::error!("Namespace {} disappeared, terminating child {}", ns_name, child);
+                kill(child, Signal::SIGTERM).ok();
+                break;
+            }
+            Err(e) => {
+                log::error!("Watchdog can not check namespace {}: {}", ns_name, e);
+                break;
+            }
+        }
+    }
+    close(dir_fd).ok();
+}
+
+async fn run_child(ns_name: &String, extra_ns: &[NsSpec], veth: Option<&VethConfig>, want_userns: bool, want_newpid: bool, channel: InitReceiver, command: &[String]) -> ! {
+    let res = split_namespace(ns_name, extra_ns, veth, want_userns, want_newpid, &channel, command).await;
 
     match res {
         Err(_) => {
@@ -104,40 +573,32 @@ async fn run_child(ns_name: &String) -> Result<(), ()> {
             std::process::abort()
         }
         Ok(()) => {
-            log::debug!("Child exited normally");
-            exit(0)
+            // split_namespace only returns Ok(()) if execvp never ran,
+            // which should not happen on a working system
+            log::error!("exec returned unexpectedly");
+            exit(1)
         }
     }
 }
 
-async fn split_namespace(ns_name: &String) -> Result<(), ()> {
-    // First create the network namespace
-    NetworkNamespace::add(ns_name.to_string()).await.map_err(|e| {
-        log::error!("Can not create namespace {}", e);
-    }).unwrap();
-
-    // Open NS path
-    let ns_path = format!("{}{}", NETNS, ns_name);
-
-    let mut open_flags = OFlag::empty();
-    open_flags.insert(OFlag::O_RDONLY);
-    open_flags.insert(OFlag::O_CLOEXEC);
-
-    let fd = match open(Path::new(&ns_path), open_flags, Mode::empty()) {
-        Ok(raw_fd) => unsafe { 
-            File::from_raw_fd(raw_fd)
-        }
-        Err(e) => {
-            log::error!("Can not open network namespace: {}", e);
+async fn split_namespace(ns_name: &String, extra_ns: &[NsSpec], veth: Option<&VethConfig>, want_userns: bool, want_newpid: bool, channel: &InitReceiver, command: &[String]) -> Result<(), ()> {
+    // If requested, enter a new user namespace before anything else and
+    // wait for the parent to finish writing our uid_map/gid_map -- we have
+    // no privileges in the new namespace until that mapping exists.
+    if want_userns {
+        if let Err(e) = unshare(CloneFlags::CLONE_NEWUSER) {
+            log::error!("Can not unshare user namespace: {}", e);
             return Err(());
         }
-    };
-    // Switch to network namespace with CLONE_NEWNET
-    if let Err(e) = setns(fd, CloneFlags::CLONE_NEWNET) {
-        log::error!("Can not set namespace to target {}: {}", ns_name, e);
-        return Err(());
+        channel.signal(Checkpoint::EnteredUserNs)?;
+        channel.wait_for(Checkpoint::MapsWritten)?;
     }
-    // unshare with CLONE_NEWNS
+
+    // unshare with CLONE_NEWNS before doing any mount work (NetworkNamespace::add()
+    // below bind-mounts under /run/netns). Under --userns, the caller only has
+    // CAP_SYS_ADMIN over the mount namespace owned by its *own* new user
+    // namespace, not the host's -- so this has to happen before we touch
+    // mounts at all, or every mount/bind-mount call fails with EPERM.
     if let Err(e) = unshare(CloneFlags::CLONE_NEWNS) {
         log::error!("Can not unshare: {}", e);
         return Err(());
@@ -149,8 +610,38 @@ async fn split_namespace(ns_name: &String) -> Result<(), ()> {
     mount_flags.insert(MsFlags::MS_REC);
     mount_flags.insert(MsFlags::MS_PRIVATE);
     if let Err(e) = mount::<PathBuf, PathBuf, str, PathBuf>(None, &PathBuf::from("/"), None, mount_flags, None) {
-        log::error!("Can not remount root directory");
-        ()
+        log::error!("Can not remount root directory: {}", e);
+    }
+
+    // First create the network namespace
+    NetworkNamespace::add(ns_name.to_string()).await.map_err(|e| {
+        log::error!("Can not create namespace {}", e);
+    }).unwrap();
+    channel.signal(Checkpoint::CreatedNetns)?;
+
+    // Open NS path -- NetworkNamespace::add() can return before this is visible
+    let ns_path = format!("{}{}", NETNS, ns_name);
+    let fd = wait_for_ns(Path::new(&ns_path)).map_err(|e| {
+        log::error!("Can not open network namespace: {}", e);
+    })?;
+
+    // While still in our own net namespace, create the veth pair and hand
+    // the peer end over to the target namespace by index
+    if let Some(cfg) = veth {
+        setup_veth_host_side(cfg, fd.as_raw_fd()).await.map_err(|e| {
+            log::error!("Can not set up veth pair {}/{}: {}", cfg.veth_name, cfg.peer_name, e);
+        })?;
+    }
+
+    // Switch to network namespace with CLONE_NEWNET
+    if let Err(e) = setns(fd, CloneFlags::CLONE_NEWNET) {
+        log::error!("Can not set namespace to target {}: {}", ns_name, e);
+        return Err(());
+    }
+
+    // Join any additional namespace types the user asked for
+    for spec in extra_ns {
+        join_namespace(spec)?;
     }
 
     // Now unmount /sys
@@ -173,17 +664,179 @@ async fn split_namespace(ns_name: &String) -> Result<(), ()> {
     // and remount a version of /sys that describes the network namespace
     if let Err(e) = mount::<PathBuf, PathBuf, str, PathBuf>(Some(&ns_name_path), &sys_path, Some("sysfs"), mount_flags, None) {
         log::error!("Can not remount /sys to namespace: {}", e);
-        ()
     }
 
+    channel.signal(Checkpoint::ReadyForConfig)?;
+
     set_lo_up().await.unwrap();
 
+    if let Some(cfg) = veth {
+        setup_veth_target_side(cfg).await.map_err(|e| {
+            log::error!("Can not configure {} inside {}: {}", cfg.peer_name, ns_name, e);
+        })?;
+    }
+
+    channel.signal(Checkpoint::ConfigDone)?;
+
+    if want_newpid {
+        enter_new_pid_namespace(channel, command)
+    } else {
+        exec_command(command)
+    }
+}
+
+// With --newpid, unshare(CLONE_NEWPID) only changes the namespace of
+// *future* children -- the calling process is never itself a member of the
+// new namespace. So fork once more: the grandchild becomes PID 1 of the new
+// namespace and execs the command, while this process reports the
+// grandchild's PID back to run_parent (which has marked itself a child
+// subreaper, see run_in_namespace) and exits immediately.
+fn enter_new_pid_namespace(channel: &InitReceiver, command: &[String]) -> Result<(), ()> {
+    if let Err(e) = unshare(CloneFlags::CLONE_NEWPID) {
+        log::error!("Can not unshare pid namespace: {}", e);
+        return Err(());
+    }
+
+    match unsafe { fork() } {
+        Ok(ForkResult::Parent { child, .. }) => {
+            channel.report_pid_ns(child)?;
+            exit(0);
+        }
+        Ok(ForkResult::Child) => exec_command(command),
+        Err(e) => {
+            log::error!("Can not fork() for pid namespace init: {}", e);
+            Err(())
+        }
+    }
+}
+
+// Create the veth pair in our (the caller's) net namespace, address and
+// bring up our side, then move the peer end into the target namespace
+// identified by `target_ns_fd` so the child can finish configuring it there.
+async fn setup_veth_host_side(cfg: &VethConfig, target_ns_fd: RawFd) -> Result<(), Error> {
+    let (connection, handle, _) = new_connection()
+        .map_err(|e| Error::NamespaceError(format!("can not open netlink socket: {}", e)))?;
+    tokio::spawn(connection);
+
+    handle.link().add().veth(cfg.veth_name.clone(), cfg.peer_name.clone()).execute().await?;
+
+    let veth_idx = handle.link().get().match_name(cfg.veth_name.clone()).execute().try_next().await?
+        .ok_or_else(|| Error::NamespaceError(format!("Can not find {} interface after creating it", cfg.veth_name)))?
+        .header.index;
+    handle.link().set(veth_idx).up().execute().await?;
+    handle.address().add(veth_idx, cfg.veth_addr.0, cfg.veth_addr.1).execute().await?;
+
+    let peer_idx = handle.link().get().match_name(cfg.peer_name.clone()).execute().try_next().await?
+        .ok_or_else(|| Error::NamespaceError(format!("Can not find {} interface after creating it", cfg.peer_name)))?
+        .header.index;
+    handle.link().set(peer_idx).setns_by_fd(target_ns_fd).execute().await?;
+
     Ok(())
 }
 
+// Finish configuring the peer end now that we are running inside the
+// target namespace: bring it up, address it, and add the default route.
+async fn setup_veth_target_side(cfg: &VethConfig) -> Result<(), Error> {
+    let (connection, handle, _) = new_connection()
+        .map_err(|e| Error::NamespaceError(format!("can not open netlink socket: {}", e)))?;
+    tokio::spawn(connection);
+
+    let peer_idx = handle.link().get().match_name(cfg.peer_name.clone()).execute().try_next().await?
+        .ok_or_else(|| Error::NamespaceError(format!("Can not find {} interface in target namespace", cfg.peer_name)))?
+        .header.index;
+    handle.link().set(peer_idx).up().execute().await?;
+    handle.address().add(peer_idx, cfg.peer_addr.0, cfg.peer_addr.1).execute().await?;
+
+    if let Some(gateway) = cfg.gateway {
+        let route_add = handle.route().add();
+        match gateway {
+            IpAddr::V4(gw) => {
+                route_add.v4().gateway(gw).execute().await?;
+            }
+            IpAddr::V6(gw) => {
+                route_add.v6().gateway(gw).execute().await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Retry opening a netns path that may not be visible yet: NetworkNamespace::add()
+// can return before the path it bind-mounted shows up. ENOENT means "keep
+// trying", EPERM means "we'll never be allowed to, stop immediately", and
+// anything else is an unexpected error we should also bail out on.
+fn wait_for_ns(path: &Path) -> Result<File, String> {
+    let mut open_flags = OFlag::empty();
+    open_flags.insert(OFlag::O_RDONLY);
+    open_flags.insert(OFlag::O_CLOEXEC);
+
+    let start = Instant::now();
+    loop {
+        match open(path, open_flags, Mode::empty()) {
+            Ok(raw_fd) => return Ok(unsafe { File::from_raw_fd(raw_fd) }),
+            Err(Errno::ENOENT) => {
+                if start.elapsed() >= NS_WAIT_TIMEOUT {
+                    return Err(format!("timed out waiting for {} to appear", path.display()));
+                }
+                sleep(NS_WAIT_POLL_INTERVAL);
+            }
+            Err(Errno::EPERM) => {
+                return Err(format!("permission denied opening {}", path.display()));
+            }
+            Err(e) => {
+                return Err(format!("can not open {}: {}", path.display(), e));
+            }
+        }
+    }
+}
+
+// Open a single `type:path` specifier and setns() into it using the clone
+// flag registered for its kind in NS_KINDS.
+fn join_namespace(spec: &NsSpec) -> Result<(), ()> {
+    let mut open_flags = OFlag::empty();
+    open_flags.insert(OFlag::O_RDONLY);
+    open_flags.insert(OFlag::O_CLOEXEC);
+
+    let fd = match open(spec.path.as_path(), open_flags, Mode::empty()) {
+        Ok(raw_fd) => unsafe { File::from_raw_fd(raw_fd) }
+        Err(e) => {
+            log::error!("Can not open {} namespace at {}: {}", spec.kind.name, spec.path.display(), e);
+            return Err(());
+        }
+    };
+    if let Err(e) = setns(fd, spec.kind.flag) {
+        log::error!("Can not join {} namespace at {}: {}", spec.kind.name, spec.path.display(), e);
+        return Err(());
+    }
+    Ok(())
+}
+
+// Replace this process image with the requested command, now that it is
+// running inside the target namespace(s) -- mirrors `ip netns exec`.
+fn exec_command(command: &[String]) -> Result<(), ()> {
+    let prog = CString::new(command[0].as_bytes()).map_err(|e| {
+        log::error!("Invalid command name {}: {}", command[0], e);
+    })?;
+    let argv: Vec<CString> = command
+        .iter()
+        .map(|arg| CString::new(arg.as_bytes()).map_err(|e| {
+            log::error!("Invalid argument {}: {}", arg, e);
+        }))
+        .collect::<Result<_, _>>()?;
+
+    match execvp(&prog, &argv) {
+        Ok(_) => unreachable!("execvp only returns on error"),
+        Err(e) => {
+            log::error!("Can not execvp {}: {}", command[0], e);
+            Err(())
+        }
+    }
+}
+
 async fn set_lo_up() -> Result<(), Error> {
     let (connection, handle, _) = new_connection().unwrap();
-    log::debug!("ARE WE STOPPING YET???");
+    tokio::spawn(connection);
     let veth_idx = handle.link().get().match_name("lo".to_string()).execute().try_next().await?
                 .ok_or_else(|| log::error!("Can not find lo interface ")).unwrap()
                 .header.index;
@@ -193,14 +846,130 @@ async fn set_lo_up() -> Result<(), Error> {
 }
 
 
-// Cargo cult from the definition in rtnetlink
-fn prep_for_fork() -> Result<(), ()> {
-    Ok(())
+// Create the socketpair the forthcoming fork()'d parent/child will use to
+// hand checkpoints back and forth. Returns (main side fd, init side fd).
+fn prep_for_fork() -> Result<(RawFd, RawFd), ()> {
+    let (main_fd, init_fd) = socketpair(AddressFamily::Unix, SockType::Stream, None, SockFlag::SOCK_CLOEXEC)
+        .map_err(|e| log::error!("Can not create sync socketpair: {}", e))?;
+    Ok((main_fd.into_raw_fd(), init_fd.into_raw_fd()))
 }
 
 fn usage() {
     eprintln!(
-        "usage: add_netns <ns_name>"
+        "usage: add_netns <ns_name> [type:path|pid ...] [--veth <name> <addr/prefix> <peer_name> <peer_addr/prefix> [gateway]] \
+         [--userns] [--uid-map inside:outside:length] [--gid-map inside:outside:length] [--watchdog] [--newpid] -- <command> [args...]\n\
+         \n\
+         type is one of: cgroup, ipc, net, mnt, pid, user, uts\n\
+         path may instead be a pid, shorthand for /proc/<pid>/ns/<type>\n\
+         --uid-map/--gid-map may be repeated; omitting them under --userns maps the caller's euid/egid to root-in-namespace\n\
+         --watchdog terminates the command if the target namespace is deleted while it is running\n\
+         --newpid runs the command as PID 1 of a new pid namespace"
     );
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ns_spec_parses_type_path() {
+        let spec = NsSpec::parse("uts:/proc/1234/ns/uts").unwrap();
+        assert_eq!(spec.kind.name, "uts");
+        assert_eq!(spec.path, PathBuf::from("/proc/1234/ns/uts"));
+    }
+
+    #[test]
+    fn ns_spec_parses_type_pid_shorthand() {
+        let spec = NsSpec::parse("net:1234").unwrap();
+        assert_eq!(spec.kind.name, "net");
+        assert_eq!(spec.path, PathBuf::from("/proc/1234/ns/net"));
+    }
+
+    #[test]
+    fn ns_spec_rejects_unknown_kind() {
+        assert!(NsSpec::parse("bogus:1234").is_err());
+    }
+
+    #[test]
+    fn ns_spec_rejects_missing_colon() {
+        assert!(NsSpec::parse("uts").is_err());
+    }
+
+    #[test]
+    fn parse_cidr_parses_addr_and_prefix() {
+        let (addr, prefix) = parse_cidr("10.0.0.1/24").unwrap();
+        assert_eq!(addr, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(prefix, 24);
+    }
+
+    #[test]
+    fn parse_cidr_rejects_missing_prefix() {
+        assert!(parse_cidr("10.0.0.1").is_err());
+    }
+
+    #[test]
+    fn parse_cidr_rejects_bad_addr() {
+        assert!(parse_cidr("not-an-addr/24").is_err());
+    }
+
+    #[test]
+    fn veth_gateway_lookahead_does_not_swallow_a_namespace_spec() {
+        let args: Vec<String> = [
+            "--veth", "v0", "10.0.0.1/24", "v0p", "10.0.0.2/24", "net:/run/netns/foo",
+        ].iter().map(|s| s.to_string()).collect();
+        let (specs, veth, _, _, _) = parse_spec_args(&args).unwrap();
+        let veth = veth.unwrap();
+        assert_eq!(veth.gateway, None);
+        assert_eq!(specs.len(), 1);
+        assert_eq!(specs[0].kind.name, "net");
+        assert_eq!(specs[0].path, PathBuf::from("/run/netns/foo"));
+    }
+
+    #[test]
+    fn veth_gateway_lookahead_accepts_a_real_gateway() {
+        let args: Vec<String> = [
+            "--veth", "v0", "10.0.0.1/24", "v0p", "10.0.0.2/24", "10.0.0.254",
+        ].iter().map(|s| s.to_string()).collect();
+        let (specs, veth, _, _, _) = parse_spec_args(&args).unwrap();
+        assert_eq!(veth.unwrap().gateway, Some("10.0.0.254".parse().unwrap()));
+        assert!(specs.is_empty());
+    }
+
+    #[test]
+    fn parse_id_map_parses_inside_outside_length() {
+        let m = parse_id_map("0:1000:1").unwrap();
+        assert_eq!((m.inside, m.outside, m.length), (0, 1000, 1));
+    }
+
+    #[test]
+    fn parse_id_map_rejects_wrong_field_count() {
+        assert!(parse_id_map("0:1000").is_err());
+        assert!(parse_id_map("0:1000:1:1").is_err());
+    }
+
+    #[test]
+    fn parse_id_map_rejects_non_numeric_field() {
+        assert!(parse_id_map("0:abc:1").is_err());
+    }
+
+    #[test]
+    fn checkpoint_byte_round_trips() {
+        for cp in [
+            Checkpoint::EnteredUserNs,
+            Checkpoint::MapsWritten,
+            Checkpoint::CreatedNetns,
+            Checkpoint::ReadyForConfig,
+            Checkpoint::ConfigDone,
+            Checkpoint::CreatedPidNs,
+        ] {
+            assert_eq!(Checkpoint::from_byte(cp.to_byte()), Ok(cp));
+        }
+    }
+
+    #[test]
+    fn checkpoint_from_byte_rejects_unknown_byte() {
+        assert!(Checkpoint::from_byte(0).is_err());
+        assert!(Checkpoint::from_byte(7).is_err());
+    }
+}
+